@@ -12,6 +12,25 @@
 //! can be generated by any tool e.g. walker.
 //! It's similar to manual_scrub tool, with the exception that manual_scrub preserves the repoid
 //! prefix for the blob, while this tool either strips it or ignores it.
+//!
+//! `--snapshot-output` writes the copied blobs into a snapshot archive (see
+//! the `snapshot` module) instead of copying them directly; `--restore-from-snapshot`
+//! restores one into the target repo in dependency order, fixing the ordering
+//! hazard described above.
+//!
+//! `--staging-dir` switches to the resumable, offset-tracked transfer path
+//! (see the `resume` module), so a crash partway through a bulk move can be
+//! continued with `--resume` instead of restarting from scratch.
+//!
+//! `--verify` re-checks each key after it's put to the target (see the
+//! `verify` module), routing mismatches to `--corrupt-keys-output`.
+
+mod resume;
+mod snapshot;
+mod verify;
+
+use std::path::PathBuf;
+use std::sync::Arc;
 
 use anyhow::anyhow;
 use anyhow::Context;
@@ -57,15 +76,28 @@ const ARG_SUCCESSFUL_KEYS: &str = "success-keys-output";
 const ARG_STRIP_SOURCE_REPO_PREFIX: &str = "strip-source-repo-prefix";
 const ARG_TARGET_INNER_BLOBSTORE_ID: &str = "target-inner-blobstore-id";
 const ARG_SOURCE_INNER_BLOBSTORE_ID: &str = "source-inner-blobstore-id";
+const ARG_SNAPSHOT_OUTPUT: &str = "snapshot-output";
+const ARG_SNAPSHOT_FORMAT: &str = "snapshot-format";
+const ARG_RESTORE_FROM_SNAPSHOT: &str = "restore-from-snapshot";
+const ARG_STAGING_DIR: &str = "staging-dir";
+const ARG_RESUME: &str = "resume";
+const ARG_CHUNK_SIZE: &str = "chunk-size";
+const ARG_VERIFY: &str = "verify";
+const ARG_CORRUPT_KEYS: &str = "corrupt-keys-output";
+
+const DEFAULT_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+const SNAPSHOT_FORMAT_PACKED: &str = "packed";
 
 struct OutputFiles {
     error_file: File,
     missing_file: File,
     successful_file: File,
+    corrupt_file: Option<File>,
 }
 
 impl OutputFiles {
-    pub async fn new(matches: &MononokeMatches<'_>) -> Result<Self, Error> {
+    pub async fn new(matches: &MononokeMatches<'_>, verify: bool) -> Result<Self, Error> {
         let error_file = Self::open_file(matches, ARG_ERROR_KEYS);
         let missing_file = Self::open_file(matches, ARG_MISSING_KEYS);
         let successful_file = Self::open_file(matches, ARG_SUCCESSFUL_KEYS);
@@ -73,10 +105,17 @@ impl OutputFiles {
         let (error_file, missing_file, successful_file) =
             future::try_join3(error_file, missing_file, successful_file).await?;
 
+        let corrupt_file = if verify {
+            Some(Self::open_file(matches, ARG_CORRUPT_KEYS).await?)
+        } else {
+            None
+        };
+
         Ok(Self {
             error_file,
             missing_file,
             successful_file,
+            corrupt_file,
         })
     }
 
@@ -88,6 +127,10 @@ impl OutputFiles {
         let file = match res {
             Ok(()) => &mut self.successful_file,
             Err(CopyError::NotFound) => &mut self.missing_file,
+            Err(CopyError::Corrupt { .. }) => self
+                .corrupt_file
+                .as_mut()
+                .ok_or_else(|| anyhow!("a key was corrupt but {} wasn't set", ARG_CORRUPT_KEYS))?,
             Err(CopyError::Error(_)) => &mut self.error_file,
         };
 
@@ -110,9 +153,11 @@ impl OutputFiles {
 }
 
 #[derive(Error, Debug)]
-enum CopyError {
+pub(crate) enum CopyError {
     #[error("Not found")]
     NotFound,
+    #[error("Corrupt: expected {expected}, got {actual}")]
+    Corrupt { expected: String, actual: String },
     #[error(transparent)]
     Error(#[from] Error),
 }
@@ -150,6 +195,12 @@ async fn run<'a>(fb: FacebookInit, matches: &'a MononokeMatches<'a>) -> Result<(
 
     let (source_repo, target_repo) = future::try_join(source_repo, target_repo).await?;
 
+    if let Some(snapshot_path) = matches.value_of(ARG_RESTORE_FROM_SNAPSHOT) {
+        let mut reader = snapshot::SnapshotReader::open(&PathBuf::from(snapshot_path)).await?;
+        snapshot::restore(&ctx, &mut reader, &target_repo.get_blobstore()).await?;
+        return Ok(());
+    }
+
     let mut keys = vec![];
     let source_repo_prefix = source_repo_id.prefix();
 
@@ -181,31 +232,88 @@ async fn run<'a>(fb: FacebookInit, matches: &'a MononokeMatches<'a>) -> Result<(
 
     let concurrency = args::get_usize(matches, ARG_CONCURRENCY, 100);
     let ignore_errors = matches.is_present(ARG_IGNORE_ERRORS);
+    let verify = matches.is_present(ARG_VERIFY);
+
+    if let Some(snapshot_output) = matches.value_of(ARG_SNAPSHOT_OUTPUT) {
+        let format = matches
+            .value_of(ARG_SNAPSHOT_FORMAT)
+            .unwrap_or(SNAPSHOT_FORMAT_PACKED);
+        let mut writer: Box<dyn snapshot::SnapshotWriter> = match format {
+            SNAPSHOT_FORMAT_PACKED => Box::new(
+                snapshot::PackedSnapshotWriter::create(&PathBuf::from(snapshot_output)).await?,
+            ),
+            other => return Err(anyhow!("unknown {}: {}", ARG_SNAPSHOT_FORMAT, other)),
+        };
+
+        let source_blobstore = source_repo.get_blobstore();
+        info!(ctx.logger(), "{} keys to snapshot", keys.len());
+        for key in &keys {
+            let maybe_value = source_blobstore.get(&ctx, key).await?;
+            let value = maybe_value.ok_or_else(|| anyhow!("key {} not found in source", key))?;
+            writer.write_blob(key, value.into_bytes().into_bytes()).await?;
+        }
+        writer.finish().await?;
+        info!(ctx.logger(), "{} keys were snapshotted", keys.len());
+        return Ok(());
+    }
+
+    let resumable_copier = match matches.value_of(ARG_STAGING_DIR) {
+        Some(staging_dir) => {
+            let chunk_size = args::get_usize(matches, ARG_CHUNK_SIZE, DEFAULT_CHUNK_SIZE);
+            let copier = resume::ResumableCopier::new(PathBuf::from(staging_dir), chunk_size);
+            if matches.is_present(ARG_RESUME) {
+                copier.ensure_dir().await?;
+            } else {
+                copier.reset().await?;
+            }
+            Some(Arc::new(copier))
+        }
+        None => None,
+    };
 
     info!(ctx.logger(), "{} keys to copy", keys.len());
     let log_step = std::cmp::max(1, keys.len() / 10);
 
     let mut s = stream::iter(keys)
-        .map(|key| async {
-            let copy_key = key.clone();
-            let res = async {
-                let source_blobstore = source_repo.get_blobstore();
-                let target_blobstore = target_repo.get_blobstore();
-                let maybe_value = source_blobstore.get(&ctx, &key).await?;
-                let value = maybe_value.ok_or(CopyError::NotFound)?;
-                debug!(ctx.logger(), "copying {}", key);
-                target_blobstore.put(&ctx, key, value.into_bytes()).await?;
-                Result::<_, CopyError>::Ok(())
-            }
-            .await;
+        .map(|key| {
+            let resumable_copier = resumable_copier.clone();
+            async {
+                let copy_key = key.clone();
+                let res = async {
+                    let source_blobstore = source_repo.get_blobstore();
+                    let target_blobstore = target_repo.get_blobstore();
+                    match resumable_copier {
+                        Some(copier) => {
+                            copier
+                                .copy_resumable(&ctx, &source_blobstore, &target_blobstore, &key, verify)
+                                .await?
+                        }
+                        None => {
+                            let maybe_value = source_blobstore.get(&ctx, &key).await?;
+                            let value = maybe_value.ok_or(CopyError::NotFound)?;
+                            debug!(ctx.logger(), "copying {}", key);
+                            let blobstore_bytes = value.into_bytes();
+                            if verify {
+                                let data = blobstore_bytes.clone().into_bytes();
+                                target_blobstore.put(&ctx, key.clone(), blobstore_bytes).await?;
+                                verify::verify_copy(&ctx, &target_blobstore, &key, &data).await?;
+                            } else {
+                                target_blobstore.put(&ctx, key, blobstore_bytes).await?;
+                            }
+                        }
+                    }
+                    Result::<_, CopyError>::Ok(())
+                }
+                .await;
 
-            (copy_key, res)
+                (copy_key, res)
+            }
         })
         .buffered(concurrency);
 
     let mut copied = 0;
     let mut processed = 0;
-    let mut output_files = OutputFiles::new(matches).await?;
+    let mut output_files = OutputFiles::new(matches, verify).await?;
     while let Some((key, res)) = s.next().await {
         let res = output_files.record_copy_result(&key, res).await;
         match res {
@@ -362,6 +470,69 @@ fn main(fb: FacebookInit) -> Result<(), Error> {
             .requires(ARG_TARGET_INNER_BLOBSTORE_ID)
             .help("In case of multiplexed blobstore this will be source id of inner blobstore"),
     )
+    .arg(
+        Arg::with_name(ARG_SNAPSHOT_OUTPUT)
+            .long(ARG_SNAPSHOT_OUTPUT)
+            .takes_value(true)
+            .required(false)
+            .conflicts_with(ARG_RESTORE_FROM_SNAPSHOT)
+            .help("Instead of copying keys directly, write them into a snapshot archive at this path"),
+    )
+    .arg(
+        Arg::with_name(ARG_SNAPSHOT_FORMAT)
+            .long(ARG_SNAPSHOT_FORMAT)
+            .takes_value(true)
+            .required(false)
+            .possible_values(&[SNAPSHOT_FORMAT_PACKED])
+            .requires(ARG_SNAPSHOT_OUTPUT)
+            .help("Snapshot archive layout to use with --snapshot-output (only \"packed\" is supported)"),
+    )
+    .arg(
+        Arg::with_name(ARG_RESTORE_FROM_SNAPSHOT)
+            .long(ARG_RESTORE_FROM_SNAPSHOT)
+            .takes_value(true)
+            .required(false)
+            .conflicts_with(ARG_SNAPSHOT_OUTPUT)
+            .help("Restore a packed snapshot archive created with --snapshot-output into the target repo, in dependency order"),
+    )
+    .arg(
+        Arg::with_name(ARG_STAGING_DIR)
+            .long(ARG_STAGING_DIR)
+            .takes_value(true)
+            .required(false)
+            .help("Stage each key's bytes here in --chunk-size windows before putting to the target, enabling --resume"),
+    )
+    .arg(
+        Arg::with_name(ARG_CHUNK_SIZE)
+            .long(ARG_CHUNK_SIZE)
+            .takes_value(true)
+            .required(false)
+            .requires(ARG_STAGING_DIR)
+            .help("Window size in bytes for staged transfers (default: 8MiB)"),
+    )
+    .arg(
+        Arg::with_name(ARG_RESUME)
+            .long(ARG_RESUME)
+            .takes_value(false)
+            .required(false)
+            .requires(ARG_STAGING_DIR)
+            .help("Continue staged transfers from --staging-dir instead of discarding leftover progress"),
+    )
+    .arg(
+        Arg::with_name(ARG_VERIFY)
+            .long(ARG_VERIFY)
+            .takes_value(false)
+            .required(false)
+            .requires(ARG_CORRUPT_KEYS)
+            .help("Re-verify each key's bytes after copying it, to catch silent corruption"),
+    )
+    .arg(
+        Arg::with_name(ARG_CORRUPT_KEYS)
+            .long(ARG_CORRUPT_KEYS)
+            .takes_value(true)
+            .required(false)
+            .help("A file to write keys that failed --verify to"),
+    )
     .get_matches(fb)?;
 
     matches.runtime().block_on(run(fb, &matches))
@@ -0,0 +1,183 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Resumable, offset-tracked blob transfers for the copy tool.
+//!
+//! Mononoke's `Blobstore` only exposes whole-value `get`/`put`, so there's
+//! no way to resume a key's transfer mid-flight against the source. What
+//! `--resume` actually buys: a key whose `put` already landed on a
+//! previous run is skipped outright, and a key's bytes are staged to a
+//! local file in fixed-size windows with the committed offset journalled
+//! after each one, so a crash partway through the local write resumes
+//! from the journal instead of restarting the file from zero.
+
+use std::path::PathBuf;
+
+use anyhow::Context;
+use anyhow::Error;
+use blobstore::Blobstore;
+use blobstore::BlobstoreBytes;
+use context::CoreContext;
+use tokio::fs;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+
+use crate::verify;
+use crate::CopyError;
+
+/// Stages each key's bytes to disk in `chunk_size` windows before doing a
+/// single `put` to the target, persisting progress so an interrupted
+/// transfer can resume the local staging write without rewriting
+/// already-staged windows. The source value itself is fetched whole on
+/// every attempt.
+pub struct ResumableCopier {
+    staging_dir: PathBuf,
+    chunk_size: usize,
+}
+
+impl ResumableCopier {
+    pub fn new(staging_dir: PathBuf, chunk_size: usize) -> Self {
+        Self {
+            staging_dir,
+            chunk_size,
+        }
+    }
+
+    /// Discards any staging state left behind by a previous, non-resumed
+    /// run, so stale partial windows can't be mistaken for progress.
+    pub async fn reset(&self) -> Result<(), Error> {
+        if fs::metadata(&self.staging_dir).await.is_ok() {
+            fs::remove_dir_all(&self.staging_dir).await?;
+        }
+        fs::create_dir_all(&self.staging_dir).await?;
+        Ok(())
+    }
+
+    pub async fn ensure_dir(&self) -> Result<(), Error> {
+        fs::create_dir_all(&self.staging_dir).await?;
+        Ok(())
+    }
+
+    fn staging_path(&self, key: &str) -> PathBuf {
+        self.staging_dir.join(format!("{}.partial", sanitize(key)))
+    }
+
+    fn journal_path(&self, key: &str) -> PathBuf {
+        self.staging_dir.join(format!("{}.committed", sanitize(key)))
+    }
+
+    async fn committed_offset(&self, key: &str) -> Result<u64, Error> {
+        match fs::read_to_string(self.journal_path(key)).await {
+            Ok(contents) => contents
+                .trim()
+                .parse()
+                .with_context(|| format!("corrupt journal for key {}", key)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(0),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn record_committed(&self, key: &str, offset: u64) -> Result<(), Error> {
+        // Write to a temp file and rename so a crash mid-write can never
+        // leave the journal holding a half-written offset.
+        let journal_path = self.journal_path(key);
+        let tmp_path = self.staging_dir.join(format!("{}.committed.tmp", sanitize(key)));
+        fs::write(&tmp_path, offset.to_string()).await?;
+        fs::rename(&tmp_path, &journal_path).await?;
+        Ok(())
+    }
+
+    /// Copies `key` from `source` to `target`. If a previous, interrupted
+    /// run already completed this key's `put`, does nothing; otherwise
+    /// fetches from source (staging to disk in resumable windows along
+    /// the way) and puts to target exactly once.
+    pub async fn copy_resumable(
+        &self,
+        ctx: &CoreContext,
+        source: &dyn Blobstore,
+        target: &dyn Blobstore,
+        key: &str,
+        verify: bool,
+    ) -> Result<(), CopyError> {
+        if target.get(ctx, key).await?.is_some() {
+            // A previous run already finished this key; nothing left to
+            // stage or fetch. This is the actual "resume" behaviour - the
+            // underlying blobstore API gives us no way to resume a single
+            // key's transfer mid-flight, only to skip ones already done.
+            let _ = fs::remove_file(self.staging_path(key)).await;
+            let _ = fs::remove_file(self.journal_path(key)).await;
+            return Ok(());
+        }
+
+        let maybe_value = source.get(ctx, key).await?;
+        let value = maybe_value.ok_or(CopyError::NotFound)?;
+        let bytes = value.into_bytes().into_bytes();
+        let total_len = bytes.len() as u64;
+
+        let mut committed = self.committed_offset(key).await?;
+        if committed > total_len {
+            // The journal refers to a longer transfer than the source now
+            // has; treat it as stale rather than trust a bogus offset.
+            committed = 0;
+        }
+
+        let staging_path = self.staging_path(key);
+        let mut staging = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&staging_path)
+            .await
+            .with_context(|| format!("failed to open staging file for {}", key))?;
+        // Drop any bytes past the committed offset (a window written just
+        // before a crash, or a stale file), so appending resumes there.
+        staging
+            .set_len(committed)
+            .await
+            .with_context(|| format!("failed to truncate staging file for {}", key))?;
+
+        let mut offset = committed;
+        while offset < total_len {
+            let end = total_len.min(offset + self.chunk_size as u64);
+            let window = bytes.slice(offset as usize..end as usize);
+            staging
+                .write_all(&window)
+                .await
+                .with_context(|| format!("failed to write staging window for {}", key))?;
+            staging
+                .flush()
+                .await
+                .with_context(|| format!("failed to flush staging window for {}", key))?;
+            offset = end;
+            self.record_committed(key, offset).await?;
+        }
+        drop(staging);
+
+        // Put from the already-fetched bytes rather than reading the staged
+        // file back: `source.get` above has no ranged-read counterpart, so
+        // the whole value is in memory regardless, and re-reading the
+        // staging file would just hold a second copy of it alongside
+        // `bytes`. The staging write above only makes the local disk copy
+        // crash-safe and resumable across runs of this tool; it doesn't
+        // make the source fetch itself resumable mid-transfer.
+        target
+            .put(ctx, key.to_string(), BlobstoreBytes::from_bytes(bytes.clone()))
+            .await?;
+
+        let _ = fs::remove_file(&staging_path).await;
+        let _ = fs::remove_file(&self.journal_path(key)).await;
+
+        if verify {
+            verify::verify_copy(ctx, target, key, &bytes).await?;
+        }
+
+        Ok(())
+    }
+}
+
+fn sanitize(key: &str) -> String {
+    key.replace('/', "_")
+}
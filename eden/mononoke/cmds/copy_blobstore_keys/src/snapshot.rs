@@ -0,0 +1,343 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Self-describing snapshot archives for the blob copy tool, restored in
+//! dependency order instead of whatever order the keys were read in.
+//!
+//! `PackedSnapshotWriter` concatenates all blob bytes into a single file,
+//! with a manifest of `(key, offset, length, sha256)` entries and a
+//! fixed-size trailer appended at the end.
+//!
+//! [`restore_tiers`] buckets keys by type prefix - content bytes first,
+//! then metadata and aliases, then derived manifests, then changesets -
+//! so a restore interrupted partway through never leaves the target
+//! pointing at a key that doesn't exist yet, and can simply be rerun.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::io::SeekFrom;
+use std::path::Path;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Error;
+use blobstore::Blobstore;
+use blobstore::BlobstoreBytes;
+use bytes::Bytes;
+use context::CoreContext;
+use sha2::Digest;
+use sha2::Sha256;
+use tokio::fs;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncSeekExt;
+use tokio::io::AsyncWriteExt;
+
+/// Magic bytes identifying a packed snapshot, written at the very end of
+/// the trailer so a truncated file can be detected quickly.
+const PACKED_MAGIC: &[u8; 8] = b"MNKSNAP1";
+/// Trailer layout: 8 bytes manifest offset (big-endian u64) + 8 byte magic.
+const TRAILER_LEN: u64 = 16;
+
+/// One entry in a packed snapshot's manifest.
+#[derive(Clone, Debug)]
+struct ManifestEntry {
+    offset: u64,
+    length: u64,
+    sha256: [u8; 32],
+}
+
+/// Writes blobs into a snapshot archive.
+///
+/// Implementations don't need to be dependency-aware themselves - ordering
+/// is the responsibility of the caller (see [`restore_tiers`] for the
+/// read-side equivalent). They only need to durably record `(key, bytes)`
+/// pairs and, on [`SnapshotWriter::finish`], make the archive readable by
+/// the matching [`SnapshotReader`].
+#[async_trait::async_trait]
+pub trait SnapshotWriter: Send {
+    async fn write_blob(&mut self, key: &str, data: Bytes) -> Result<(), Error>;
+
+    async fn finish(self: Box<Self>) -> Result<(), Error>;
+}
+
+/// Concatenates blob bytes into a single stream, followed by a manifest
+/// and a fixed trailer pointing at the manifest's offset.
+pub struct PackedSnapshotWriter {
+    file: File,
+    cursor: u64,
+    manifest: BTreeMap<String, ManifestEntry>,
+}
+
+impl PackedSnapshotWriter {
+    pub async fn create(path: &Path) -> Result<Self, Error> {
+        let file = File::create(path)
+            .await
+            .with_context(|| format!("failed to create {}", path.display()))?;
+        Ok(Self {
+            file,
+            cursor: 0,
+            manifest: BTreeMap::new(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl SnapshotWriter for PackedSnapshotWriter {
+    async fn write_blob(&mut self, key: &str, data: Bytes) -> Result<(), Error> {
+        let mut hasher = Sha256::new();
+        hasher.update(&data);
+        let sha256 = hasher.finalize().into();
+
+        let offset = self.cursor;
+        let length = data.len() as u64;
+        self.file.write_all(&data).await?;
+        self.cursor += length;
+
+        self.manifest.insert(
+            key.to_string(),
+            ManifestEntry {
+                offset,
+                length,
+                sha256,
+            },
+        );
+        Ok(())
+    }
+
+    async fn finish(mut self: Box<Self>) -> Result<(), Error> {
+        let manifest_offset = self.cursor;
+
+        for (key, entry) in &self.manifest {
+            let key_bytes = key.as_bytes();
+            self.file.write_all(&(key_bytes.len() as u32).to_be_bytes()).await?;
+            self.file.write_all(key_bytes).await?;
+            self.file.write_all(&entry.offset.to_be_bytes()).await?;
+            self.file.write_all(&entry.length.to_be_bytes()).await?;
+            self.file.write_all(&entry.sha256).await?;
+        }
+        self.file
+            .write_all(&(self.manifest.len() as u64).to_be_bytes())
+            .await?;
+
+        self.file.write_all(&manifest_offset.to_be_bytes()).await?;
+        self.file.write_all(PACKED_MAGIC).await?;
+        self.file.flush().await?;
+        Ok(())
+    }
+}
+
+/// Reads blobs back out of a packed snapshot archive by seeking via its
+/// manifest, without having to load the whole archive into memory.
+pub struct SnapshotReader {
+    file: File,
+    manifest: BTreeMap<String, ManifestEntry>,
+}
+
+impl SnapshotReader {
+    pub async fn open(path: &Path) -> Result<Self, Error> {
+        let mut file = File::open(path)
+            .await
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        let len = file.metadata().await?.len();
+        if len < TRAILER_LEN {
+            return Err(anyhow!("{} is too small to be a snapshot", path.display()));
+        }
+
+        file.seek(SeekFrom::End(-(TRAILER_LEN as i64))).await?;
+        let mut trailer = [0u8; TRAILER_LEN as usize];
+        file.read_exact(&mut trailer).await?;
+        let (offset_bytes, magic) = trailer.split_at(8);
+        if magic != PACKED_MAGIC {
+            return Err(anyhow!("{} is not a packed snapshot", path.display()));
+        }
+        let manifest_offset = u64::from_be_bytes(offset_bytes.try_into().unwrap());
+
+        file.seek(SeekFrom::Start(manifest_offset)).await?;
+        let manifest_len = len - TRAILER_LEN - manifest_offset;
+        let mut manifest_bytes = vec![0u8; manifest_len as usize];
+        file.read_exact(&mut manifest_bytes).await?;
+
+        let mut cursor = &manifest_bytes[..];
+        // Count is stored as the last 8 bytes of the manifest region.
+        let (entries_bytes, count_bytes) = cursor.split_at(manifest_bytes.len() - 8);
+        let count = u64::from_be_bytes(count_bytes.try_into().unwrap());
+        cursor = entries_bytes;
+
+        let mut manifest = BTreeMap::new();
+        for _ in 0..count {
+            let (key_len_bytes, rest) = cursor.split_at(4);
+            let key_len = u32::from_be_bytes(key_len_bytes.try_into().unwrap()) as usize;
+            let (key_bytes, rest) = rest.split_at(key_len);
+            let key = String::from_utf8(key_bytes.to_vec())
+                .with_context(|| "manifest entry key is not valid utf8")?;
+            let (offset_bytes, rest) = rest.split_at(8);
+            let offset = u64::from_be_bytes(offset_bytes.try_into().unwrap());
+            let (length_bytes, rest) = rest.split_at(8);
+            let length = u64::from_be_bytes(length_bytes.try_into().unwrap());
+            let (sha256_bytes, rest) = rest.split_at(32);
+            let sha256 = sha256_bytes.try_into().unwrap();
+            cursor = rest;
+
+            manifest.insert(
+                key,
+                ManifestEntry {
+                    offset,
+                    length,
+                    sha256,
+                },
+            );
+        }
+
+        Ok(Self { file, manifest })
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.manifest.keys().map(String::as_str)
+    }
+
+    pub async fn read_blob(&mut self, key: &str) -> Result<Option<Bytes>, Error> {
+        let entry = match self.manifest.get(key) {
+            Some(entry) => entry.clone(),
+            None => return Ok(None),
+        };
+
+        self.file.seek(SeekFrom::Start(entry.offset)).await?;
+        let mut buf = vec![0u8; entry.length as usize];
+        self.file.read_exact(&mut buf).await?;
+
+        let mut hasher = Sha256::new();
+        hasher.update(&buf);
+        let actual: [u8; 32] = hasher.finalize().into();
+        if actual != entry.sha256 {
+            return Err(anyhow!("snapshot entry for {} failed checksum", key));
+        }
+
+        Ok(Some(Bytes::from(buf)))
+    }
+}
+
+/// The dependency tiers blobstore keys are restored in, least-dependent
+/// first. A key whose prefix isn't recognised is restored in the last
+/// tier, on the assumption that unknown data is more likely to depend on
+/// known data than the reverse.
+const RESTORE_TIERS: &[&[&str]] = &[
+    &["content.", "chunk."],
+    &["content-metadata.", "alias."],
+    &["fsnode.", "unode.", "skeleton-manifest.", "deleted-manifest."],
+    &["changeset.", "hgchangeset.", "hgmanifest."],
+];
+
+/// Groups `keys` into the ordered restore tiers described above. Keys that
+/// don't match any known prefix end up in a final catch-all tier.
+fn restore_tiers(keys: Vec<String>) -> Vec<Vec<String>> {
+    let mut tiers: Vec<Vec<String>> = (0..RESTORE_TIERS.len() + 1).map(|_| Vec::new()).collect();
+
+    for key in keys {
+        let tier = RESTORE_TIERS
+            .iter()
+            .position(|prefixes| prefixes.iter().any(|prefix| key.starts_with(prefix)))
+            .unwrap_or(RESTORE_TIERS.len());
+        tiers[tier].push(key);
+    }
+
+    tiers
+}
+
+/// Restores every key in `reader` into `target`, tier by tier, so that a
+/// target left partially restored by a crash is never internally
+/// dangling. Keys already present in `target` are skipped, which lets an
+/// interrupted restore simply be re-run to completion.
+pub async fn restore(
+    ctx: &CoreContext,
+    reader: &mut SnapshotReader,
+    target: &dyn Blobstore,
+) -> Result<(), Error> {
+    let keys: Vec<String> = reader.keys().map(str::to_string).collect();
+
+    for tier in restore_tiers(keys) {
+        for key in tier {
+            if target.get(ctx, &key).await?.is_some() {
+                // Already restored by a previous, interrupted run.
+                continue;
+            }
+
+            let data = reader
+                .read_blob(&key)
+                .await?
+                .ok_or_else(|| anyhow!("key {} vanished from its own snapshot manifest", key))?;
+            target
+                .put(ctx, key.clone(), BlobstoreBytes::from_bytes(data))
+                .await
+                .with_context(|| format!("failed to restore {}", key))?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn restore_tiers_groups_by_real_key_prefixes() {
+        let digest = "a".repeat(64);
+        let keys = vec![
+            format!("changeset.blake2.{}", digest),
+            format!("content.blake2.{}", digest),
+            format!("chunk.blake2.{}", digest),
+            format!("fsnode.blake2.{}", digest),
+            format!("alias.sha1.{}", digest),
+            "some.unknown.key".to_string(),
+        ];
+
+        let tiers = restore_tiers(keys);
+
+        assert_eq!(tiers[0], vec![format!("content.blake2.{}", digest), format!("chunk.blake2.{}", digest)]);
+        assert_eq!(tiers[1], vec![format!("alias.sha1.{}", digest)]);
+        assert_eq!(tiers[2], vec![format!("fsnode.blake2.{}", digest)]);
+        assert_eq!(tiers[3], vec![format!("changeset.blake2.{}", digest)]);
+        assert_eq!(tiers[4], vec!["some.unknown.key".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn packed_snapshot_round_trips_through_reader() {
+        let path = std::env::temp_dir().join(format!(
+            "copy_blobstore_keys_snapshot_test_{}",
+            std::process::id()
+        ));
+
+        let mut writer = PackedSnapshotWriter::create(&path).await.unwrap();
+        writer
+            .write_blob("content.blake2.a", Bytes::from_static(b"hello"))
+            .await
+            .unwrap();
+        writer
+            .write_blob("changeset.blake2.b", Bytes::from_static(b"world"))
+            .await
+            .unwrap();
+        Box::new(writer).finish().await.unwrap();
+
+        let mut reader = SnapshotReader::open(&path).await.unwrap();
+        let mut keys: Vec<&str> = reader.keys().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["changeset.blake2.b", "content.blake2.a"]);
+
+        assert_eq!(
+            reader.read_blob("content.blake2.a").await.unwrap(),
+            Some(Bytes::from_static(b"hello"))
+        );
+        assert_eq!(
+            reader.read_blob("changeset.blake2.b").await.unwrap(),
+            Some(Bytes::from_static(b"world"))
+        );
+        assert_eq!(reader.read_blob("missing.key").await.unwrap(), None);
+
+        let _ = fs::remove_file(&path).await;
+    }
+}
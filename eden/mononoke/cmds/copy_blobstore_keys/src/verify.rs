@@ -0,0 +1,151 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Post-put integrity verification for the copy tool.
+//!
+//! Content-addressed keys (`content.*`, `chunk.*`) get their embedded
+//! digest recomputed from the bytes just written and compared, catching
+//! corruption an opaque `get`/`put` round-trip wouldn't. Anything else
+//! falls back to a read-back-and-compare against the target.
+
+use blake2::digest::Update;
+use blake2::digest::VariableOutput;
+use blake2::Blake2bVar;
+use blobstore::Blobstore;
+use bytes::Bytes;
+use context::CoreContext;
+
+use crate::CopyError;
+
+/// Recognised content-addressed key prefixes, and the hex digest length
+/// (in characters, i.e. `digest bytes * 2`) each one embeds. A key that
+/// doesn't match one of these is treated as opaque and verified by
+/// read-back instead.
+const CONTENT_ADDRESSED_PREFIXES: &[(&str, usize)] = &[("content.blake2.", 64), ("chunk.blake2.", 64)];
+
+/// Returns the hex digest embedded in `key`, if `key` looks like one of
+/// the content-addressed key formats this tool knows how to recompute.
+fn embedded_digest(key: &str) -> Option<&str> {
+    for (prefix, len) in CONTENT_ADDRESSED_PREFIXES {
+        if let Some(rest) = key.strip_prefix(prefix) {
+            if rest.len() == *len && rest.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Some(rest);
+            }
+        }
+    }
+    None
+}
+
+/// Recomputes the hex-encoded BLAKE2b digest of `data`, at the given
+/// output size in bytes. BLAKE2b's digest length is part of its
+/// parameter block, not just a truncation applied after the fact, so
+/// this must be run with the exact digest size the key format uses
+/// (e.g. 32 bytes for a 64 hex character key) rather than hashing at a
+/// different size (say, the 64-byte `Blake2b512` default) and slicing
+/// the result - the two produce entirely different bytes.
+fn compute_digest(data: &[u8], digest_bytes: usize) -> String {
+    let mut hasher =
+        Blake2bVar::new(digest_bytes).expect("digest_bytes is a supported blake2b output size");
+    hasher.update(data);
+    let mut out = vec![0u8; digest_bytes];
+    hasher
+        .finalize_variable(&mut out)
+        .expect("out is sized to digest_bytes");
+    hex::encode(out)
+}
+
+/// Verifies that `data` is the bytes that were just written to `key` in
+/// `target`, returning `Ok(())` on success or `CopyError::Corrupt` with the
+/// expected and actual digests/bytes on a mismatch.
+pub async fn verify_copy(
+    ctx: &CoreContext,
+    target: &dyn Blobstore,
+    key: &str,
+    data: &Bytes,
+) -> Result<(), CopyError> {
+    match embedded_digest(key) {
+        Some(expected) => {
+            // `expected` is hex-encoded, so its byte length is half its
+            // character length.
+            let actual = compute_digest(data, expected.len() / 2);
+            if actual != expected {
+                return Err(CopyError::Corrupt {
+                    expected: expected.to_string(),
+                    actual,
+                });
+            }
+            Ok(())
+        }
+        None => {
+            let readback = target.get(ctx, key).await.map_err(CopyError::Error)?;
+            match readback {
+                Some(value) if &value.into_bytes().into_bytes() == data => Ok(()),
+                Some(_) => Err(CopyError::Corrupt {
+                    expected: format!("{} bytes just written", data.len()),
+                    actual: "different bytes read back from target".to_string(),
+                }),
+                None => Err(CopyError::Corrupt {
+                    expected: format!("{} bytes just written", data.len()),
+                    actual: "key missing from target on read-back".to_string(),
+                }),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn embedded_digest_recognises_known_prefixes() {
+        let digest = "a".repeat(64);
+        assert_eq!(
+            embedded_digest(&format!("content.blake2.{}", digest)),
+            Some(digest.as_str())
+        );
+        assert_eq!(
+            embedded_digest(&format!("chunk.blake2.{}", digest)),
+            Some(digest.as_str())
+        );
+    }
+
+    #[test]
+    fn embedded_digest_rejects_wrong_length_or_non_hex() {
+        assert_eq!(embedded_digest("content.blake2.abcd"), None);
+        assert_eq!(
+            embedded_digest(&format!("content.blake2.{}", "z".repeat(64))),
+            None
+        );
+    }
+
+    #[test]
+    fn embedded_digest_rejects_unknown_prefix() {
+        assert_eq!(
+            embedded_digest(&format!("unode.blake2.{}", "a".repeat(64))),
+            None
+        );
+    }
+
+    #[test]
+    fn compute_digest_is_deterministic_and_size_sensitive() {
+        let data = b"hello world";
+        let digest_32 = compute_digest(data, 32);
+        assert_eq!(digest_32.len(), 64);
+        assert_eq!(digest_32, compute_digest(data, 32));
+
+        // A 32-byte BLAKE2b digest is not a truncation of the 64-byte one:
+        // they're computed with different parameter blocks.
+        let digest_64 = compute_digest(data, 64);
+        assert_ne!(digest_32, digest_64[..64]);
+    }
+
+    #[test]
+    fn compute_digest_differs_for_different_input() {
+        assert_ne!(compute_digest(b"hello", 32), compute_digest(b"world", 32));
+    }
+}
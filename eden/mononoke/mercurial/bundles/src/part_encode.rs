@@ -16,6 +16,7 @@ use anyhow::Error;
 use anyhow::Result;
 use bytes::Bytes as BytesNew;
 use bytes_old::Bytes;
+use bytes_old::BytesMut;
 use futures_ext::BoxStream;
 use futures_ext::StreamExt;
 use futures_old::Async;
@@ -38,6 +39,69 @@ impl Debug for ChunkStream {
     }
 }
 
+/// Don't send clients chunks that are too large or (outside of the last
+/// chunk of a part) too small: coalesces small chunks from the inner
+/// stream together, and splits oversized ones, so the wire framing a
+/// generator produces is never pathological regardless of how it chose to
+/// chunk its own output.
+#[derive(Debug)]
+struct RechunkBuffer {
+    buf: BytesMut,
+    min_size: usize,
+    max_size: usize,
+    /// Set once the inner stream has yielded its last item, so the next
+    /// poll knows to flush whatever remains instead of waiting for more.
+    stream_done: bool,
+}
+
+impl RechunkBuffer {
+    fn new(min_size: usize, max_size: usize) -> Self {
+        Self {
+            buf: BytesMut::new(),
+            min_size,
+            max_size,
+            stream_done: false,
+        }
+    }
+
+    fn is_at_max(&self) -> bool {
+        self.buf.len() >= self.max_size
+    }
+
+    fn is_at_min(&self) -> bool {
+        self.buf.len() >= self.min_size
+    }
+
+    fn take_max_chunk(&mut self) -> Bytes {
+        self.buf.split_to(self.max_size).freeze()
+    }
+
+    fn take_remaining(&mut self) -> Bytes {
+        let len = self.buf.len();
+        self.buf.split_to(len).freeze()
+    }
+}
+
+/// Bundles together everything `poll_generating` needs to drive a
+/// `Generating` payload: the inner stream, its rechunking state, and (if
+/// the generator fails) what to do about it.
+struct GeneratingState {
+    stream: ChunkStream,
+    rechunk: RechunkBuffer,
+    part_id: PartId,
+    on_error: Option<PartHeaderType>,
+}
+
+impl Debug for GeneratingState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GeneratingState")
+            .field("rechunk", &self.rechunk)
+            .field("part_id", &self.part_id)
+            .field("on_error", &self.on_error)
+            .finish()
+    }
+}
+
 #[derive(Debug)]
 pub enum PartEncodeData {
     None,
@@ -45,9 +109,19 @@ pub enum PartEncodeData {
     Generated(ChunkStream),
 }
 
+/// Default lower bound for a rechunked payload chunk. Below this, it's
+/// worth waiting a little longer for more bytes rather than sending a
+/// tiny chunk - unless it's the very last chunk of the part.
+const DEFAULT_MIN_CHUNK_SIZE: usize = 8 * 1024;
+/// Default upper bound for a rechunked payload chunk.
+const DEFAULT_MAX_CHUNK_SIZE: usize = 1024 * 1024;
+
 pub struct PartEncodeBuilder {
     headerb: PartHeaderBuilder,
     data: PartEncodeData,
+    min_chunk_size: usize,
+    max_chunk_size: usize,
+    on_error: Option<PartHeaderType>,
 }
 
 #[derive(Debug)]
@@ -57,9 +131,19 @@ pub struct PartEncode {
 
 #[derive(Debug)]
 enum GenerationState {
-    NotStarted(PartHeader, PartEncodeData),
+    NotStarted(PartHeader, PartEncodeData, usize, usize, PartId, Option<PartHeaderType>),
+    /// The header hasn't been emitted yet: we're waiting on the inner
+    /// stream's first item so that, if it fails immediately, we can still
+    /// swap in an error part instead of this part's real header.
+    Peeking {
+        header: PartHeader,
+        stream: ChunkStream,
+        rechunk: RechunkBuffer,
+        part_id: PartId,
+        on_error: Option<PartHeaderType>,
+    },
     Fixed(Chunk),
-    Generating(ChunkStream),
+    Generating(GeneratingState),
     EmptyChunk,
     Done,
     Invalid,
@@ -76,6 +160,9 @@ impl PartEncodeBuilder {
         Ok(PartEncodeBuilder {
             headerb: PartHeaderBuilder::new(part_type, true)?,
             data: PartEncodeData::None,
+            min_chunk_size: DEFAULT_MIN_CHUNK_SIZE,
+            max_chunk_size: DEFAULT_MAX_CHUNK_SIZE,
+            on_error: None,
         })
     }
 
@@ -83,9 +170,33 @@ impl PartEncodeBuilder {
         Ok(PartEncodeBuilder {
             headerb: PartHeaderBuilder::new(part_type, false)?,
             data: PartEncodeData::None,
+            min_chunk_size: DEFAULT_MIN_CHUNK_SIZE,
+            max_chunk_size: DEFAULT_MAX_CHUNK_SIZE,
+            on_error: None,
         })
     }
 
+    /// If the generator supplied to `set_data_generated`/`set_data_future`
+    /// fails before it has produced any payload bytes, send a mandatory
+    /// `part_type` error part in place of this part's header instead of
+    /// hard-failing the whole bundle stream. Once the generator has yielded
+    /// its first item, this part's real header has gone out and the wire
+    /// format can no longer un-commit to it, so a later failure still
+    /// propagates as a stream error regardless of this setting.
+    pub fn on_error_emit_part(&mut self, part_type: PartHeaderType) -> &mut Self {
+        self.on_error = Some(part_type);
+        self
+    }
+
+    /// Overrides the target chunk size window used to rechunk a generated
+    /// payload (see `set_data_generated`). Has no effect on fixed payloads,
+    /// which are always sent as a single chunk.
+    pub fn set_chunk_size_bounds(&mut self, min_size: usize, max_size: usize) -> &mut Self {
+        self.min_chunk_size = min_size;
+        self.max_chunk_size = max_size;
+        self
+    }
+
     #[inline]
     pub fn add_mparam<S, B>(&mut self, key: S, val: B) -> Result<&mut Self>
     where
@@ -135,8 +246,16 @@ impl PartEncodeBuilder {
     }
 
     pub fn build(self, part_id: PartId) -> PartEncode {
+        let header = self.headerb.build(part_id.clone());
         PartEncode {
-            state: GenerationState::NotStarted(self.headerb.build(part_id), self.data),
+            state: GenerationState::NotStarted(
+                header,
+                self.data,
+                self.min_chunk_size,
+                self.max_chunk_size,
+                part_id,
+                self.on_error,
+            ),
         }
     }
 }
@@ -161,6 +280,7 @@ impl PartEncode {
         //
         // The state machine captures the generation as:
         // NotStarted = header not output yet
+        // Peeking = header not output yet, waiting on generator's first item
         // Generating = payload currently being generated by inner stream
         // Fixed = fixed-length payload (no generation, just one chunk)
         // EmptyChunk = end of payload (or no payload)
@@ -169,33 +289,261 @@ impl PartEncode {
         use self::GenerationState::*;
 
         match state {
-            NotStarted(header, data) => {
-                let header_chunk = header.encode();
-                let next_state = match data {
-                    PartEncodeData::Fixed(b) => Fixed(b),
-                    PartEncodeData::None => EmptyChunk,
-                    PartEncodeData::Generated(ChunkStream(stream)) => {
-                        Generating(ChunkStream(stream))
+            NotStarted(header, data, min_chunk_size, max_chunk_size, part_id, on_error) => {
+                match data {
+                    PartEncodeData::Fixed(b) => {
+                        (Ok(Async::Ready(Some(header.encode()))), Fixed(b))
                     }
-                };
-                (Ok(Async::Ready(Some(header_chunk))), next_state)
-            }
-            Generating(ChunkStream(mut stream)) => {
-                match stream.poll() {
-                    Ok(Async::Ready(Some(v))) => {
-                        // TODO: don't send too large or too small chunks to clients
-                        (Ok(Async::Ready(Some(v))), Generating(ChunkStream(stream)))
+                    PartEncodeData::None => {
+                        (Ok(Async::Ready(Some(header.encode()))), EmptyChunk)
                     }
-                    Ok(Async::Ready(None)) => (Ok(Async::Ready(Some(Chunk::empty()))), Done),
-                    Ok(Async::NotReady) => (Ok(Async::NotReady), Generating(ChunkStream(stream))),
-                    // TODO: produce an error part for (some kinds of?) errors
-                    Err(e) => (Err(e), Generating(ChunkStream(stream))),
+                    PartEncodeData::Generated(stream) => Self::poll_peeking(
+                        header,
+                        stream,
+                        RechunkBuffer::new(min_chunk_size, max_chunk_size),
+                        part_id,
+                        on_error,
+                    ),
                 }
             }
+            Peeking {
+                header,
+                stream,
+                rechunk,
+                part_id,
+                on_error,
+            } => Self::poll_peeking(header, stream, rechunk, part_id, on_error),
+            Generating(state) => Self::poll_generating(state),
             Fixed(chunk) => (Ok(Async::Ready(Some(chunk))), EmptyChunk),
             EmptyChunk => (Ok(Async::Ready(Some(Chunk::empty()))), Done),
             Done => (Ok(Async::Ready(None)), Done),
             Invalid => panic!("invalid state"),
         }
     }
+
+    /// Drives the inner stream until its first item resolves, without
+    /// having emitted this part's header yet. A successful (or empty)
+    /// first item commits to the real header, queuing the item for
+    /// `poll_generating` to pick up; a failure, if `on_error` is set,
+    /// instead emits a synthetic error part and ends the stream there.
+    fn poll_peeking(
+        header: PartHeader,
+        stream: ChunkStream,
+        mut rechunk: RechunkBuffer,
+        part_id: PartId,
+        on_error: Option<PartHeaderType>,
+    ) -> (Poll<Option<Chunk>, Error>, GenerationState) {
+        use self::GenerationState::*;
+
+        let ChunkStream(mut inner) = stream;
+
+        match inner.poll() {
+            Ok(Async::Ready(Some(v))) => {
+                rechunk.buf.extend_from_slice(&v.into_bytes());
+                (
+                    Ok(Async::Ready(Some(header.encode()))),
+                    Generating(GeneratingState {
+                        stream: ChunkStream(inner),
+                        rechunk,
+                        part_id,
+                        on_error,
+                    }),
+                )
+            }
+            Ok(Async::Ready(None)) => {
+                rechunk.stream_done = true;
+                (
+                    Ok(Async::Ready(Some(header.encode()))),
+                    Generating(GeneratingState {
+                        stream: ChunkStream(inner),
+                        rechunk,
+                        part_id,
+                        on_error,
+                    }),
+                )
+            }
+            Ok(Async::NotReady) => (
+                Ok(Async::NotReady),
+                Peeking {
+                    header,
+                    stream: ChunkStream(inner),
+                    rechunk,
+                    part_id,
+                    on_error,
+                },
+            ),
+            Err(e) => match on_error {
+                Some(error_part_type) => match Self::build_error_part(error_part_type, part_id, &e) {
+                    Ok(error_chunk) => (Ok(Async::Ready(Some(error_chunk))), EmptyChunk),
+                    Err(build_err) => (Err(build_err), Invalid),
+                },
+                None => (Err(e), Invalid),
+            },
+        }
+    }
+
+    /// Builds a single-chunk, header-only error part: a mandatory
+    /// `error_part_type` part whose `message`/`hint` aparams come from the
+    /// top and (if present) second frame of `err`'s cause chain.
+    ///
+    /// Not unit tested here: `PartHeaderType`/`PartId` come from
+    /// `part_header`, which isn't part of this checkout.
+    fn build_error_part(error_part_type: PartHeaderType, part_id: PartId, err: &Error) -> Result<Chunk> {
+        let mut chain = err.chain();
+        let message = chain
+            .next()
+            .map(|cause| cause.to_string())
+            .unwrap_or_else(|| err.to_string());
+
+        let mut headerb = PartHeaderBuilder::new(error_part_type, true)?;
+        headerb.add_aparam("message", message)?;
+        if let Some(hint) = chain.next() {
+            headerb.add_aparam("hint", hint.to_string())?;
+        }
+        Ok(headerb.build(part_id).encode())
+    }
+
+    /// Drives the rechunking buffer: pulls from `stream`, coalescing and
+    /// splitting payload bytes to `rechunk`'s target window, until either a
+    /// full-size chunk is ready to emit or the inner stream can't give us
+    /// more right now.
+    fn poll_generating(
+        state: GeneratingState,
+    ) -> (Poll<Option<Chunk>, Error>, GenerationState) {
+        use self::GenerationState::*;
+
+        let GeneratingState {
+            stream,
+            mut rechunk,
+            part_id,
+            on_error,
+        } = state;
+        let ChunkStream(mut inner) = stream;
+
+        loop {
+            if rechunk.is_at_max() {
+                let chunk_bytes = rechunk.take_max_chunk();
+                let next = GeneratingState {
+                    stream: ChunkStream(inner),
+                    rechunk,
+                    part_id,
+                    on_error,
+                };
+                return match Chunk::new(chunk_bytes) {
+                    Ok(chunk) => (Ok(Async::Ready(Some(chunk))), Generating(next)),
+                    Err(e) => (Err(e), Generating(next)),
+                };
+            }
+
+            if rechunk.stream_done {
+                if rechunk.buf.is_empty() {
+                    return (Ok(Async::Ready(Some(Chunk::empty()))), Done);
+                }
+                let chunk_bytes = rechunk.take_remaining();
+                return match Chunk::new(chunk_bytes) {
+                    Ok(chunk) => (Ok(Async::Ready(Some(chunk))), EmptyChunk),
+                    Err(e) => (Err(e), Done),
+                };
+            }
+
+            match inner.poll() {
+                Ok(Async::Ready(Some(v))) => {
+                    rechunk.buf.extend_from_slice(&v.into_bytes());
+                    // Loop back around: we may now have a full window, or
+                    // may need to pull further chunks to reach one.
+                }
+                Ok(Async::Ready(None)) => {
+                    rechunk.stream_done = true;
+                }
+                Ok(Async::NotReady) => {
+                    if rechunk.is_at_min() {
+                        // We already have enough to send a reasonably
+                        // sized chunk and the generator has nothing ready
+                        // right now - flush rather than stall it.
+                        let chunk_bytes = rechunk.take_remaining();
+                        let next = GeneratingState {
+                            stream: ChunkStream(inner),
+                            rechunk,
+                            part_id,
+                            on_error,
+                        };
+                        return match Chunk::new(chunk_bytes) {
+                            Ok(chunk) => (Ok(Async::Ready(Some(chunk))), Generating(next)),
+                            Err(e) => (Err(e), Generating(next)),
+                        };
+                    }
+                    // Not enough buffered yet: propagate backpressure
+                    // instead of sending a pathologically small chunk.
+                    return (
+                        Ok(Async::NotReady),
+                        Generating(GeneratingState {
+                            stream: ChunkStream(inner),
+                            rechunk,
+                            part_id,
+                            on_error,
+                        }),
+                    );
+                }
+                // The header for this part has already been flushed by
+                // this point (see `poll_peeking`), so there's no part left
+                // to swap out from under it; a failure here still hard-
+                // fails the stream even if `on_error` was set.
+                Err(e) => {
+                    return (
+                        Err(e),
+                        Generating(GeneratingState {
+                            stream: ChunkStream(inner),
+                            rechunk,
+                            part_id,
+                            on_error,
+                        }),
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rechunk_buffer_flushes_once_max_size_is_reached() {
+        let mut rechunk = RechunkBuffer::new(4, 8);
+        rechunk.buf.extend_from_slice(b"0123456789");
+        assert!(rechunk.is_at_max());
+
+        let chunk = rechunk.take_max_chunk();
+        assert_eq!(&chunk[..], b"01234567");
+        assert_eq!(&rechunk.buf[..], b"89");
+    }
+
+    #[test]
+    fn rechunk_buffer_is_at_min_only_once_threshold_crossed() {
+        let mut rechunk = RechunkBuffer::new(4, 8);
+        assert!(!rechunk.is_at_min());
+
+        rechunk.buf.extend_from_slice(b"abc");
+        assert!(!rechunk.is_at_min());
+
+        rechunk.buf.extend_from_slice(b"d");
+        assert!(rechunk.is_at_min());
+    }
+
+    #[test]
+    fn rechunk_buffer_take_remaining_drains_a_short_final_buffer() {
+        let mut rechunk = RechunkBuffer::new(4, 8);
+        rechunk.buf.extend_from_slice(b"xy");
+
+        let chunk = rechunk.take_remaining();
+        assert_eq!(&chunk[..], b"xy");
+        assert!(rechunk.buf.is_empty());
+    }
+
+    #[test]
+    fn rechunk_buffer_take_remaining_on_empty_buffer_yields_empty_chunk() {
+        let mut rechunk = RechunkBuffer::new(4, 8);
+        assert_eq!(&rechunk.take_remaining()[..], b"");
+    }
 }
@@ -7,9 +7,19 @@
 
 //! Support for converting Mononoke data structures into in-memory blobs.
 
+use std::pin::Pin;
+
 use anyhow::Result;
 use blobstore::BlobstoreBytes;
 use bytes::Bytes;
+use bytes::BytesMut;
+use futures::future;
+use futures::stream;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use futures::TryStreamExt;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt;
 
 use crate::typed_hash::ChangesetId;
 use crate::typed_hash::ContentChunkId;
@@ -49,6 +59,10 @@ impl<Id> Blob<Id> {
     pub fn data(&self) -> &Bytes {
         &self.data
     }
+
+    pub fn into_parts(self) -> (Id, Bytes) {
+        (self.id, self.data)
+    }
 }
 
 pub type ChangesetBlob = Blob<ChangesetId>;
@@ -77,3 +91,83 @@ pub trait BlobstoreValue: Sized + Send {
     fn into_blob(self) -> Blob<Self::Key>;
     fn from_blob(blob: Blob<Self::Key>) -> Result<Self>;
 }
+
+/// A blob whose payload hasn't been materialized yet, so it can be fed to
+/// a streaming blobstore put without ever buffering the whole serialized
+/// object in memory. `id` is still required up front - most `Key` types
+/// here are content hashes the caller derives (e.g. incrementally,
+/// alongside producing the bytes) before a blob is ever constructed, just
+/// as the synchronous `BlobstoreValue::into_blob` path already requires.
+///
+/// This is deliberately a separate type from [`Blob`] rather than a lazy
+/// variant of it: every existing caller of `Blob::data()`/`Blob::len()`
+/// expects an already-materialized payload, and there's no way to audit
+/// all of them from this slice of the tree. `finalize` is the bridge back
+/// to a plain `Blob` once a caller needs one.
+pub struct StreamingBlob<Id> {
+    id: Id,
+    data: BoxStream<'static, Result<Bytes>>,
+}
+
+impl<Id> StreamingBlob<Id> {
+    pub fn new(id: Id, data: BoxStream<'static, Result<Bytes>>) -> Self {
+        Self { id, data }
+    }
+
+    pub fn id(&self) -> &Id {
+        &self.id
+    }
+
+    /// Consumes this blob as a stream of byte chunks.
+    pub fn into_stream(self) -> BoxStream<'static, Result<Bytes>> {
+        self.data
+    }
+
+    /// Drains the stream into a single in-memory payload, yielding a
+    /// regular, eager `Blob`.
+    pub async fn finalize(mut self) -> Result<Blob<Id>> {
+        let mut buf = BytesMut::new();
+        while let Some(chunk) = self.data.try_next().await? {
+            buf.extend_from_slice(&chunk);
+        }
+        Ok(Blob::new(self.id, buf.freeze()))
+    }
+}
+
+/// Streaming counterpart to [`BlobstoreValue`], for types whose serialized
+/// form (fsnodes, fastlog batches, content metadata) can be large enough
+/// that buffering all of it into a single `Bytes` before a blobstore `put`
+/// is wasteful. A real implementation overrides both methods to serialize
+/// and deserialize incrementally; the default methods below just adapt the
+/// supertrait's eager `into_blob`/`from_blob`, so a type that doesn't need
+/// real streaming yet can still opt in with an empty `impl
+/// AsyncBlobstoreValue for T {}`.
+///
+/// This is a supertrait-with-defaults rather than a blanket impl over every
+/// `BlobstoreValue` on purpose: a blanket impl here would mean coherence
+/// forbids any type from ever writing a real, overriding implementation of
+/// this trait (E0119), permanently locking it to the non-streaming default.
+#[async_trait::async_trait]
+pub trait AsyncBlobstoreValue: BlobstoreValue + Send
+where
+    Self::Key: Send,
+{
+    /// Serializes `self` into a stream of byte chunks rather than one
+    /// eagerly-materialized `Bytes`.
+    fn into_blob_stream(self) -> StreamingBlob<Self::Key> {
+        let (id, data) = self.into_blob().into_parts();
+        StreamingBlob::new(id, stream::once(future::ready(Ok(data))).boxed())
+    }
+
+    /// Reconstructs `Self` from a stream of its serialized bytes, given
+    /// the `id` they were (or will be) stored under. As with
+    /// `BlobstoreValue::from_blob`, the id is supplied by the caller
+    /// rather than recomputed from the bytes: most `Key` types here are
+    /// content hashes the caller already has from the blobstore key it
+    /// fetched.
+    async fn from_blob_reader(id: Self::Key, mut reader: Pin<Box<dyn AsyncRead + Send>>) -> Result<Self> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await?;
+        Self::from_blob(Blob::new(id, Bytes::from(data)))
+    }
+}
@@ -0,0 +1,316 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! A seekable, chunk-at-a-time reader over a content object laid out as a
+//! sequence of [`ContentChunkId`] blobs, so that large files can be
+//! streamed or randomly accessed without ever materializing the whole
+//! [`ContentBlob`] in memory.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Context as PollContext;
+use std::task::Poll;
+
+use anyhow::Error;
+use blobstore::Blobstore;
+use bytes::Bytes;
+use context::CoreContext;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use lru::LruCache;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncSeek;
+use tokio::io::ReadBuf;
+
+use crate::typed_hash::ContentChunkId;
+
+/// The position and size of one chunk within the content object's overall
+/// byte layout.
+#[derive(Clone, Debug)]
+pub struct ContentChunkPointer {
+    pub chunk_id: ContentChunkId,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Number of fetched chunks to keep in memory at once. Chosen to cover a
+/// handful of sequential reads plus the occasional seek back, without
+/// holding an unbounded amount of blob data resident.
+const CHUNK_CACHE_SIZE: usize = 8;
+
+type ChunkFetch = BoxFuture<'static, Result<Bytes, Error>>;
+
+/// An [`AsyncRead`] + [`AsyncSeek`] view over a chunked content object.
+///
+/// Chunks are fetched from the blobstore lazily, on demand, and kept in a
+/// small LRU so that re-reading a recently visited chunk (e.g. after a
+/// short seek-back) doesn't re-fetch it.
+pub struct ChunkedContentReader {
+    ctx: CoreContext,
+    blobstore: Arc<dyn Blobstore>,
+    /// Chunk pointers, ordered by `offset`, covering the whole content.
+    chunks: Vec<ContentChunkPointer>,
+    total_len: u64,
+    cursor: u64,
+    cache: LruCache<ContentChunkId, Bytes>,
+    pending: Option<(usize, ChunkFetch)>,
+}
+
+impl ChunkedContentReader {
+    pub fn new(ctx: CoreContext, blobstore: Arc<dyn Blobstore>, chunks: Vec<ContentChunkPointer>) -> Self {
+        let total_len = chunks.last().map_or(0, |c| c.offset + c.length);
+        Self {
+            ctx,
+            blobstore,
+            chunks,
+            total_len,
+            cursor: 0,
+            cache: LruCache::new(CHUNK_CACHE_SIZE),
+            pending: None,
+        }
+    }
+
+    /// Returns the index of the chunk covering `pos`, if any, skipping
+    /// zero-length chunks. Returns `None` at `pos == total_len` (EOF).
+    fn chunk_for_offset(&self, pos: u64) -> Option<usize> {
+        locate_chunk(&self.chunks, pos)
+    }
+
+    fn start_fetch(&self, idx: usize) -> ChunkFetch {
+        let ctx = self.ctx.clone();
+        let blobstore = self.blobstore.clone();
+        let key = self.chunks[idx].chunk_id.blobstore_key();
+        async move {
+            let data = blobstore
+                .get(&ctx, &key)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("content chunk {} is missing", key))?;
+            Ok(data.into_bytes().into_bytes())
+        }
+        .boxed()
+    }
+}
+
+impl AsyncRead for ChunkedContentReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut PollContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if this.cursor >= this.total_len {
+                return Poll::Ready(Ok(()));
+            }
+
+            let idx = match this.chunk_for_offset(this.cursor) {
+                Some(idx) => idx,
+                None => return Poll::Ready(Ok(())),
+            };
+            let pointer = this.chunks[idx].clone();
+
+            if let Some(bytes) = this.cache.get(&pointer.chunk_id) {
+                let chunk_pos = (this.cursor - pointer.offset) as usize;
+                let available = &bytes[chunk_pos..];
+                let to_copy = available.len().min(buf.remaining());
+                buf.put_slice(&available[..to_copy]);
+                this.cursor += to_copy as u64;
+                return Poll::Ready(Ok(()));
+            }
+
+            // Not cached: make sure a fetch for this chunk is in flight.
+            if this.pending.as_ref().map(|(pending_idx, _)| *pending_idx) != Some(idx) {
+                this.pending = Some((idx, this.start_fetch(idx)));
+            }
+
+            let (_, fetch) = this.pending.as_mut().expect("just populated above");
+            match fetch.as_mut().poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(err)) => {
+                    this.pending = None;
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, err)));
+                }
+                Poll::Ready(Ok(bytes)) => {
+                    this.pending = None;
+                    this.cache.put(pointer.chunk_id, bytes);
+                    // Loop back around: the chunk is now cached.
+                }
+            }
+        }
+    }
+}
+
+impl AsyncSeek for ChunkedContentReader {
+    fn start_seek(self: Pin<&mut Self>, position: io::SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+
+        // Clamp past-EOF seeks rather than erroring, matching the
+        // behaviour of `std::io::Cursor` and friends.
+        this.cursor = resolve_seek(this.cursor, this.total_len, position)?;
+
+        // Drop any fetch that's no longer relevant to the new position.
+        if let Some((idx, _)) = &this.pending {
+            if this.chunk_for_offset(this.cursor) != Some(*idx) {
+                this.pending = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut PollContext<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.cursor))
+    }
+}
+
+/// The offset and length of one chunk, for the purposes of locating which
+/// chunk covers a given position. Implemented for [`ContentChunkPointer`]
+/// and, in tests, for lightweight fixtures that don't need a real
+/// [`ContentChunkId`].
+trait ChunkSpan {
+    fn start(&self) -> u64;
+    fn len(&self) -> u64;
+}
+
+impl ChunkSpan for ContentChunkPointer {
+    fn start(&self) -> u64 {
+        self.offset
+    }
+
+    fn len(&self) -> u64 {
+        self.length
+    }
+}
+
+/// The index of the chunk in `chunks` (ordered by offset) covering `pos`,
+/// skipping past any zero-length chunks - a zero-length chunk can never
+/// itself be "covered" by a read, since it has nothing to read.
+fn locate_chunk<T: ChunkSpan>(chunks: &[T], pos: u64) -> Option<usize> {
+    // Chunks are ordered by offset, so a binary search on the cumulative
+    // start offset finds the covering chunk directly.
+    match chunks.binary_search_by(|chunk| chunk.start().cmp(&pos)) {
+        Ok(idx) => {
+            // `pos` lands exactly on a chunk boundary; skip any
+            // zero-length chunks that start there.
+            first_non_empty_from(chunks, idx)
+        }
+        Err(0) => None,
+        Err(idx) => {
+            let candidate = idx - 1;
+            let chunk = &chunks[candidate];
+            if pos < chunk.start() + chunk.len() {
+                Some(candidate)
+            } else {
+                first_non_empty_from(chunks, idx)
+            }
+        }
+    }
+}
+
+fn first_non_empty_from<T: ChunkSpan>(chunks: &[T], start: usize) -> Option<usize> {
+    (start..chunks.len()).find(|&i| chunks[i].len() > 0)
+}
+
+/// Resolves a `seek` against `total_len`, clamping a past-EOF target to
+/// `total_len` rather than erroring, and rejecting a target that would
+/// land before the start of the content.
+fn resolve_seek(cursor: u64, total_len: u64, position: io::SeekFrom) -> io::Result<u64> {
+    let new_cursor = match position {
+        io::SeekFrom::Start(offset) => offset,
+        io::SeekFrom::End(delta) => {
+            let target = total_len as i64 + delta;
+            if target < 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "seek to a negative position",
+                ));
+            }
+            target as u64
+        }
+        io::SeekFrom::Current(delta) => {
+            let target = cursor as i64 + delta;
+            if target < 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "seek to a negative position",
+                ));
+            }
+            target as u64
+        }
+    };
+
+    Ok(new_cursor.min(total_len))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Span {
+        offset: u64,
+        length: u64,
+    }
+
+    impl ChunkSpan for Span {
+        fn start(&self) -> u64 {
+            self.offset
+        }
+
+        fn len(&self) -> u64 {
+            self.length
+        }
+    }
+
+    fn span(offset: u64, length: u64) -> Span {
+        Span { offset, length }
+    }
+
+    #[test]
+    fn locate_chunk_finds_the_covering_chunk() {
+        let chunks = vec![span(0, 5), span(5, 3), span(8, 4)];
+        assert_eq!(locate_chunk(&chunks, 0), Some(0));
+        assert_eq!(locate_chunk(&chunks, 4), Some(0));
+        assert_eq!(locate_chunk(&chunks, 5), Some(1));
+        assert_eq!(locate_chunk(&chunks, 11), Some(2));
+        assert_eq!(locate_chunk(&chunks, 12), None); // EOF
+    }
+
+    #[test]
+    fn locate_chunk_skips_zero_length_chunks_at_a_boundary() {
+        let chunks = vec![span(0, 0), span(0, 5), span(5, 0), span(5, 3)];
+        assert_eq!(locate_chunk(&chunks, 0), Some(1));
+        assert_eq!(locate_chunk(&chunks, 5), Some(3));
+    }
+
+    #[test]
+    fn locate_chunk_skips_trailing_zero_length_chunks_at_eof() {
+        let chunks = vec![span(0, 5), span(5, 0)];
+        assert_eq!(locate_chunk(&chunks, 5), None);
+    }
+
+    #[test]
+    fn resolve_seek_clamps_past_eof_to_total_len() {
+        assert_eq!(resolve_seek(0, 10, io::SeekFrom::Start(100)).unwrap(), 10);
+        assert_eq!(resolve_seek(0, 10, io::SeekFrom::End(100)).unwrap(), 10);
+    }
+
+    #[test]
+    fn resolve_seek_handles_relative_seeks() {
+        assert_eq!(resolve_seek(4, 10, io::SeekFrom::Current(2)).unwrap(), 6);
+        assert_eq!(resolve_seek(10, 10, io::SeekFrom::End(0)).unwrap(), 10);
+        assert_eq!(resolve_seek(10, 10, io::SeekFrom::End(-10)).unwrap(), 0);
+    }
+
+    #[test]
+    fn resolve_seek_rejects_negative_targets() {
+        assert!(resolve_seek(0, 10, io::SeekFrom::Current(-1)).is_err());
+        assert!(resolve_seek(0, 10, io::SeekFrom::End(-11)).is_err());
+    }
+}
+
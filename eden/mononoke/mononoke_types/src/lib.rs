@@ -0,0 +1,16 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Core Mononoke data types.
+//!
+//! NOTE: this crate root only declares the modules present in this
+//! checkout; the rest of `mononoke_types` (notably `typed_hash`, which
+//! `content_chunk_reader` depends on) exists upstream but isn't part of
+//! this snapshot.
+
+pub mod blob;
+pub mod content_chunk_reader;